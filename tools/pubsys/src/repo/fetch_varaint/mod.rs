@@ -1,6 +1,8 @@
 //! The fetch_variant module owns the 'fetch-variant' subcommand and provides methods for fetching
 //! a given variant and download its image targets.
 
+mod sink;
+
 use crate::repo::{error as repo_error, repo_urls};
 use crate::{repo, Args};
 use clap::Parser;
@@ -8,9 +10,13 @@ use futures::TryStreamExt;
 use futures::{stream, StreamExt};
 use log::{info, trace};
 use pubsys_config::InfraConfig;
-use snafu::{OptionExt, ResultExt};
+use sha2::{Digest as Sha2Digest, Sha256};
+use sink::{target_sink, TargetSink};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
 use std::io::{ErrorKind, Read};
 use std::path::PathBuf;
+use std::sync::Arc;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 use tokio_util::io::SyncIoBridge;
 use tough::{Repository, RepositoryLoader};
@@ -36,17 +42,158 @@ pub(crate) struct FetchVariantArgs {
     root_role_path: PathBuf,
 
     #[arg(long)]
-    /// Where to store the downloaded img files
-    outdir: PathBuf,
+    /// Where to store the downloaded img files: a local path, or a storage URI such as
+    /// `s3://bucket/prefix` or `file:///path`
+    outdir: String,
 
     #[arg(long)]
     /// The varaint name witout extension
     buildsys_name_friendly: PathBuf,
+
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(usize).range(1..))]
+    /// The maximum number of targets to download at the same time (must be at least 1)
+    max_concurrent_downloads: usize,
+
+    #[arg(long)]
+    /// Expected sha256 digest of a downloaded (decompressed) target, given as
+    /// `<target>=sha256:<hex>`; may be repeated once per target. Targets without a matching
+    /// entry are not digest-checked.
+    digest: Vec<String>,
+
+    #[arg(long)]
+    /// Re-download and overwrite targets that already exist at the destination
+    force: bool,
+
+    #[arg(long, value_enum)]
+    /// Force a specific compression format instead of detecting it from each target's file
+    /// extension
+    compression: Option<Compression>,
+}
+
+/// Compression format a target's content is stored in, detected from its file extension unless
+/// overridden by `--compression`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Compression {
+    Lz4,
+    Zstd,
+    Gzip,
+    None,
+}
+
+impl Compression {
+    fn from_target_name(target: &str) -> Self {
+        if target.ends_with(".lz4") {
+            Self::Lz4
+        } else if target.ends_with(".zst") {
+            Self::Zstd
+        } else if target.ends_with(".gz") {
+            Self::Gzip
+        } else {
+            Self::None
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Lz4 => "lz4",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+            Self::None => "none",
+        }
+    }
+}
+
+/// Parses the `--digest <target>=sha256:<hex>` arguments into a lookup table.
+fn expected_digests(digest_args: &[String]) -> Result<HashMap<String, String>, Error> {
+    digest_args
+        .iter()
+        .map(|arg| {
+            let (target, digest) = arg
+                .split_once('=')
+                .context(error::InvalidDigestArgSnafu { arg: arg.clone() })?;
+            Ok((target.to_string(), digest.to_string()))
+        })
+        .collect()
+}
+
+/// Finds every target belonging to the requested variant by matching the repo's `targets`
+/// metadata against the `buildsys_name_friendly` prefix (e.g. kernel, initrd, boot and data
+/// images all share the same prefix with different suffixes).
+fn variant_targets(repo: &Repository, buildsys_name_friendly: &str) -> Vec<String> {
+    matching_targets(
+        repo.targets()
+            .signed
+            .targets
+            .keys()
+            .map(|target_name| target_name.raw().to_string()),
+        buildsys_name_friendly,
+    )
+}
+
+/// Filters `targets` down to the ones that belong to `buildsys_name_friendly`. A target belongs
+/// to the variant only if the prefix is followed by `.` or `-`, not just any continuation, so
+/// `aws-k8s-1.2` does not match `aws-k8s-1.24.*` targets. Because `-` is accepted, a variant whose
+/// name plus `-` is a prefix of another's (e.g. `aws-k8s-1.24` vs `aws-k8s-1.24-nvidia`) also
+/// matches that other variant's targets.
+fn matching_targets(
+    targets: impl Iterator<Item = String>,
+    buildsys_name_friendly: &str,
+) -> Vec<String> {
+    targets
+        .filter(|target| match target.strip_prefix(buildsys_name_friendly) {
+            Some(rest) => rest.starts_with(['.', '-']),
+            None => false,
+        })
+        .collect()
 }
 
-async fn download_target(repo: Repository, target: &str, outdir: PathBuf) -> Result<(), Error> {
-    let file_path = outdir.join(target);
+/// Decides whether an already-present target can be skipped: never if `force` is set or the
+/// target isn't present yet; otherwise, presence alone is enough unless the caller gave an
+/// expected digest, in which case the existing content's digest has to match it too.
+fn should_skip_existing(
+    exists: bool,
+    existing_digest: Option<&str>,
+    expected_digest: Option<&str>,
+    force: bool,
+) -> bool {
+    if force || !exists {
+        return false;
+    }
+    match expected_digest {
+        None => true,
+        Some(expected) => existing_digest == Some(expected),
+    }
+}
+
+async fn download_target(
+    repo: Repository,
+    target: String,
+    sink: Arc<dyn TargetSink>,
+    expected_digest: Option<String>,
+    force: bool,
+    compression_override: Option<Compression>,
+) -> Result<(), Error> {
+    // Skip targets we already have: with no expected digest, mere presence is enough to trust a
+    // prior run finished; with one, we only skip if the existing content still matches it. Only
+    // fetch the existing digest (which has to read back the whole target) when we'd actually use it.
+    let exists = sink.exists(&target).await?;
+    let existing_digest = if !force && exists && expected_digest.is_some() {
+        Some(sink.digest(&target).await?)
+    } else {
+        None
+    };
+    if should_skip_existing(
+        exists,
+        existing_digest.as_deref(),
+        expected_digest.as_deref(),
+        force,
+    ) {
+        info!("Target '{}' already present, skipping", target);
+        return Ok(());
+    }
+
     let target = target
+        .as_str()
         .try_into()
         .context(error::TargetNameSnafu { target })?;
     let stream = match repo.read_target(&target).await {
@@ -66,31 +213,98 @@ async fn download_target(repo: Repository, target: &str, outdir: PathBuf) -> Res
 
     // Convert the stream to a blocking Read object.
     let mapped_err = stream.map(|next| next.map_err(|e| std::io::Error::new(ErrorKind::Other, e)));
-    let lz4_async_read = mapped_err.into_async_read().compat();
-    let lz4_bytes = SyncIoBridge::new(lz4_async_read);
+    let compressed_async_read = mapped_err.into_async_read().compat();
+    let compressed_bytes = SyncIoBridge::new(compressed_async_read);
 
-    let mut reader = lz4::Decoder::new(lz4_bytes).context(error::Lz4DecodeSnafu {
-        target: target.raw(),
-    })?;
+    let compression =
+        compression_override.unwrap_or_else(|| Compression::from_target_name(target.raw()));
+    let reader: Box<dyn Read> = match compression {
+        Compression::Lz4 => Box::new(lz4::Decoder::new(compressed_bytes).context(
+            error::DecompressSnafu {
+                target: target.raw(),
+                format: compression.as_str(),
+            },
+        )?),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(compressed_bytes).context(
+            error::DecompressSnafu {
+                target: target.raw(),
+                format: compression.as_str(),
+            },
+        )?),
+        Compression::Gzip => Box::new(flate2::read::GzDecoder::new(compressed_bytes)),
+        Compression::None => Box::new(compressed_bytes),
+    };
+
+    // Stream the decompressed image straight to a local temp file while hashing it, rather than
+    // buffering the whole (possibly multi-GB) image in memory; the temp file is what actually
+    // gets handed to the sink below, and is cleaned up automatically once we're done with it.
+    let mut hashing_reader = DigestReader::new(reader);
+    let mut temp_file = tempfile::NamedTempFile::new().context(error::CreateTempFileSnafu)?;
+    std::io::copy(&mut hashing_reader, temp_file.as_file_mut()).context(
+        error::ReadTargetSnafu {
+            target: target.raw(),
+        },
+    )?;
+
+    if let Some(expected) = expected_digest {
+        let actual = hashing_reader.hex_digest();
+        if actual != expected {
+            return error::DigestMismatchSnafu {
+                target: target.raw(),
+                expected,
+                actual,
+            }
+            .fail();
+        }
+    }
+
+    sink.put_file(target.raw(), temp_file.path()).await?;
 
-    // write the image file to the outdir
-    let mut file = std::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&file_path)
-        .context(error::OpenImageFileSnafu { path: file_path })?;
-    std::io::copy(&mut reader, &mut file).context(error::WriteUpdateSnafu)?;
     Ok(())
 }
 
+/// Wraps a `Read` and feeds every byte that passes through it into a running SHA-256 hash, so we
+/// can verify the *decompressed* image content as it streams to a local temp file, without ever
+/// holding the whole image in memory.
+struct DigestReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> DigestReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn hex_digest(self) -> String {
+        format!("sha256:{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
 async fn fetch_varaint(
     root_role_path: &PathBuf,
     metadata_url: Url,
     targets_url: &Url,
-    outdir: PathBuf,
+    outdir: &str,
     buildsys_name_friendly: &str,
+    max_concurrent_downloads: usize,
+    digests: HashMap<String, String>,
+    force: bool,
+    compression_override: Option<Compression>,
 ) -> Result<(), Error> {
+    let sink: Arc<dyn TargetSink> = target_sink(outdir)?;
+
     // Load the repository
     let repo = RepositoryLoader::new(
         &repo::root_bytes(root_role_path).await?,
@@ -103,10 +317,32 @@ async fn fetch_varaint(
         metadata_base_url: metadata_url.clone(),
     })?;
 
-    let target = format!("{}.img.lz4", buildsys_name_friendly);
+    // Find every target that belongs to this variant and fetch them all in parallel, bounded by
+    // `max_concurrent_downloads`, so a variant with many images (kernel, initrd, boot, data, ...)
+    // doesn't have to be fetched one subcommand invocation at a time.
+    let targets = variant_targets(&repo, buildsys_name_friendly);
+    ensure!(
+        !targets.is_empty(),
+        error::NoMatchingTargetsSnafu {
+            variant: buildsys_name_friendly,
+        }
+    );
 
-    // Retrieve the targets and download them
-    download_target(repo, &target, outdir).await?;
+    stream::iter(targets)
+        .map(|target| {
+            let expected_digest = digests.get(&target).cloned();
+            download_target(
+                repo.clone(),
+                target,
+                sink.clone(),
+                expected_digest,
+                force,
+                compression_override,
+            )
+        })
+        .buffer_unordered(max_concurrent_downloads)
+        .try_collect::<Vec<_>>()
+        .await?;
 
     Ok(())
 }
@@ -141,36 +377,61 @@ pub(crate) async fn run(args: &Args, fetch_varaint_args: &FetchVariantArgs) -> R
         &fetch_varaint_args.root_role_path,
         repo_urls.0,
         &repo_urls.1,
-        fetch_varaint_args.outdir.clone(),
+        &fetch_varaint_args.outdir,
         fetch_varaint_args.buildsys_name_friendly.to_str().unwrap(),
+        fetch_varaint_args.max_concurrent_downloads,
+        expected_digests(&fetch_varaint_args.digest)?,
+        fetch_varaint_args.force,
+        fetch_varaint_args.compression,
     )
     .await
 }
 
 mod error {
     use snafu::{Backtrace, Snafu};
-    use std::path::PathBuf;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub(crate) enum Error {
-        #[snafu(display("Failed to decode LZ4-compressed target {}: {}", target, source))]
-        Lz4Decode {
-            target: String,
+        #[snafu(display("Failed to create a local temp file to stage a target in: {}", source))]
+        CreateTempFile {
             source: std::io::Error,
             backtrace: Backtrace,
         },
 
-        #[snafu(display("Failed writing update data to file: {}", source))]
-        WriteUpdate {
+        #[snafu(display(
+            "Digest mismatch for target '{}': expected {}, got {}",
+            target,
+            expected,
+            actual
+        ))]
+        DigestMismatch {
+            target: String,
+            expected: String,
+            actual: String,
+        },
+
+        #[snafu(display("Invalid --digest argument '{}', expected <target>=sha256:<hex>", arg))]
+        InvalidDigestArg { arg: String },
+
+        #[snafu(display(
+            "Failed to decode '{}'-compressed target {}: {}",
+            format,
+            target,
+            source
+        ))]
+        Decompress {
+            target: String,
+            format: String,
             source: std::io::Error,
             backtrace: Backtrace,
         },
 
-        #[snafu(display("Failed to open image file path {}: {}", path.display(), source))]
-        OpenImageFile {
-            path: PathBuf,
+        #[snafu(display("Failed to read decompressed target data: {}", source))]
+        ReadTarget {
+            target: String,
             source: std::io::Error,
+            backtrace: Backtrace,
         },
 
         #[snafu(context(false), display("{}", source))]
@@ -179,12 +440,21 @@ mod error {
             source: Box<crate::repo::Error>,
         },
 
+        #[snafu(context(false), display("{}", source))]
+        Sink {
+            #[snafu(source(from(super::sink::Error, Box::new)))]
+            source: Box<super::sink::Error>,
+        },
+
         #[snafu(display("Error reading bytes from stream: {}", source))]
         Stream { source: tough::error::Error },
 
         #[snafu(display("Missing target: {}", target))]
         TargetMissing { target: String },
 
+        #[snafu(display("No targets found for variant '{}'", variant))]
+        NoMatchingTargets { variant: String },
+
         #[snafu(display("Invalid target name '{}': {}", target, source))]
         TargetName {
             target: String,
@@ -201,3 +471,129 @@ mod error {
     }
 }
 pub(crate) use error::Error;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_skip_existing_requires_presence() {
+        assert!(!should_skip_existing(false, None, None, false));
+    }
+
+    #[test]
+    fn should_skip_existing_trusts_presence_without_a_digest() {
+        assert!(should_skip_existing(true, None, None, false));
+    }
+
+    #[test]
+    fn should_skip_existing_checks_digest_when_given_one() {
+        assert!(should_skip_existing(
+            true,
+            Some("sha256:abc123"),
+            Some("sha256:abc123"),
+            false
+        ));
+        assert!(!should_skip_existing(
+            true,
+            Some("sha256:abc123"),
+            Some("sha256:def456"),
+            false
+        ));
+    }
+
+    #[test]
+    fn should_skip_existing_never_skips_when_forced() {
+        assert!(!should_skip_existing(
+            true,
+            Some("sha256:abc123"),
+            Some("sha256:abc123"),
+            true
+        ));
+    }
+
+    #[test]
+    fn matching_targets_anchors_on_separator() {
+        let targets = vec![
+            "aws-k8s-1.24.kernel.lz4".to_string(),
+            "aws-k8s-1.24-nvidia.kernel.lz4".to_string(),
+            "aws-k8s-1.24-nvidia-extra.kernel.lz4".to_string(),
+            "unrelated.kernel.lz4".to_string(),
+        ];
+
+        let matched = matching_targets(targets.into_iter(), "aws-k8s-1.24");
+
+        assert_eq!(
+            matched,
+            vec![
+                "aws-k8s-1.24.kernel.lz4".to_string(),
+                "aws-k8s-1.24-nvidia.kernel.lz4".to_string(),
+                "aws-k8s-1.24-nvidia-extra.kernel.lz4".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn matching_targets_rejects_bare_prefix_match() {
+        let targets = vec!["aws-k8s-1.24".to_string()];
+
+        // No separator after the prefix at all, so it's not a match.
+        assert!(matching_targets(targets.into_iter(), "aws-k8s-1.2").is_empty());
+    }
+
+    #[test]
+    fn expected_digests_parses_target_digest_pairs() {
+        let digests = expected_digests(&[
+            "kernel.img.lz4=sha256:abc123".to_string(),
+            "initrd.img.lz4=sha256:def456".to_string(),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            digests.get("kernel.img.lz4"),
+            Some(&"sha256:abc123".to_string())
+        );
+        assert_eq!(
+            digests.get("initrd.img.lz4"),
+            Some(&"sha256:def456".to_string())
+        );
+    }
+
+    #[test]
+    fn expected_digests_rejects_missing_equals() {
+        assert!(expected_digests(&["kernel.img.lz4-sha256:abc123".to_string()]).is_err());
+    }
+
+    #[test]
+    fn compression_from_target_name_detects_known_extensions() {
+        assert_eq!(
+            Compression::from_target_name("kernel.img.lz4"),
+            Compression::Lz4
+        );
+        assert_eq!(
+            Compression::from_target_name("kernel.img.zst"),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::from_target_name("kernel.img.gz"),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_target_name("kernel.img"),
+            Compression::None
+        );
+    }
+
+    #[test]
+    fn digest_reader_computes_sha256_while_reading() {
+        let mut reader = DigestReader::new(b"hello world".as_slice());
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+
+        assert_eq!(buf, b"hello world");
+        assert_eq!(
+            reader.hex_digest(),
+            "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+    }
+}
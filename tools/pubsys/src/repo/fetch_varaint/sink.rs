@@ -0,0 +1,279 @@
+//! Pluggable storage for fetched target images.
+//!
+//! `--outdir` used to be a bare local directory. To let CI jobs push fetched variant images
+//! straight to object storage, it now accepts any URI that `object_store::parse_url` understands
+//! (e.g. `s3://bucket/prefix`). A bare local path or a `file://` URI is handled directly by
+//! [`LocalFileSink`] instead of going through `object_store`, so the crash-safety guarantee below
+//! is ours to keep (and test), not an assumption about another crate's internals.
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use object_store::path::Path as ObjectPath;
+use object_store::ObjectStore;
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use url::Url;
+
+/// Size of the read buffer used to hash existing targets without loading them into memory whole.
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A destination that downloaded target images are written to.
+#[async_trait]
+pub(crate) trait TargetSink: Send + Sync {
+    /// Publishes the already-downloaded, decompressed file at `local_path` (digest-verified when
+    /// an expected digest was given for it) to this sink under `target`'s name.
+    async fn put_file(&self, target: &str, local_path: &Path) -> Result<(), Error>;
+
+    /// Returns whether `target` already exists in this sink, without reading its content.
+    async fn exists(&self, target: &str) -> Result<bool, Error>;
+
+    /// Computes the `sha256:<hex>` digest of `target`'s existing content in this sink.
+    async fn digest(&self, target: &str) -> Result<String, Error>;
+}
+
+/// Builds the right [`TargetSink`] for an `--outdir` value: a bare filesystem path or a
+/// `file://` URI goes to [`LocalFileSink`]; anything else (e.g. `s3://bucket/prefix`) goes to
+/// [`ObjectStoreSink`].
+pub(crate) fn target_sink(outdir: &str) -> Result<Arc<dyn TargetSink>, Error> {
+    let local_path = match Url::parse(outdir) {
+        Ok(url) if url.scheme() != "file" => {
+            let (store, prefix) =
+                object_store::parse_url(&url).context(error::ParseOutdirSnafu { outdir })?;
+            return Ok(Arc::new(ObjectStoreSink {
+                store: Arc::from(store),
+                prefix,
+            }));
+        }
+        Ok(url) => url
+            .to_file_path()
+            .map_err(|_| error::InvalidOutdirSnafu { outdir }.build())?,
+        Err(_) => PathBuf::from(outdir),
+    };
+
+    Ok(Arc::new(LocalFileSink::new(local_path)?))
+}
+
+/// A [`TargetSink`] backed directly by the local filesystem. `put_file` copies the staged file
+/// into a `<target>.part` file in `outdir` and only `rename`s it to its final name once the copy
+/// has fully succeeded, so an interrupted run can never leave a truncated target behind under its
+/// real (non-`.part`) name.
+pub(crate) struct LocalFileSink {
+    outdir: PathBuf,
+}
+
+impl LocalFileSink {
+    pub(crate) fn new(outdir: PathBuf) -> Result<Self, Error> {
+        std::fs::create_dir_all(&outdir).context(error::LocalOutdirSnafu {
+            outdir: outdir.display().to_string(),
+        })?;
+        Ok(Self { outdir })
+    }
+}
+
+#[async_trait]
+impl TargetSink for LocalFileSink {
+    async fn put_file(&self, target: &str, local_path: &Path) -> Result<(), Error> {
+        let dest = self.outdir.join(target);
+        let part = self.outdir.join(format!("{target}.part"));
+
+        std::fs::copy(local_path, &part).context(error::WriteLocalFileSnafu { target })?;
+        std::fs::rename(&part, &dest).context(error::WriteLocalFileSnafu { target })?;
+        Ok(())
+    }
+
+    async fn exists(&self, target: &str) -> Result<bool, Error> {
+        Ok(self.outdir.join(target).is_file())
+    }
+
+    async fn digest(&self, target: &str) -> Result<String, Error> {
+        let mut file = std::fs::File::open(self.outdir.join(target))
+            .context(error::ReadLocalFileSnafu { target })?;
+        let mut hasher = Sha256::new();
+        let mut buf = [0u8; HASH_CHUNK_SIZE];
+        loop {
+            let n = file
+                .read(&mut buf)
+                .context(error::ReadLocalFileSnafu { target })?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(format!("sha256:{:x}", hasher.finalize()))
+    }
+}
+
+/// A [`TargetSink`] backed by an `object_store::ObjectStore`, used for any `--outdir` URI whose
+/// scheme isn't `file` (e.g. `s3://bucket/prefix`).
+pub(crate) struct ObjectStoreSink {
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+}
+
+#[async_trait]
+impl TargetSink for ObjectStoreSink {
+    // Streams the staged file through a multipart upload instead of buffering it in memory, so a
+    // multi-GB target never has to be held as a second in-memory copy on top of the one
+    // `download_target` already staged to disk while hashing it.
+    async fn put_file(&self, target: &str, local_path: &Path) -> Result<(), Error> {
+        let path = self.prefix.child(target);
+        let (multipart_id, mut writer) = self
+            .store
+            .put_multipart(&path)
+            .await
+            .context(error::PutTargetSnafu { target })?;
+
+        let mut file = tokio::fs::File::open(local_path)
+            .await
+            .context(error::OpenLocalFileSnafu { target })?;
+
+        let copied = tokio::io::copy(&mut file, &mut writer)
+            .await
+            .and(tokio::io::AsyncWriteExt::shutdown(&mut writer).await);
+        if let Err(source) = copied {
+            let _ = self.store.abort_multipart(&path, &multipart_id).await;
+            return Err(source).context(error::WriteMultipartSnafu { target });
+        }
+        Ok(())
+    }
+
+    async fn exists(&self, target: &str) -> Result<bool, Error> {
+        let path = self.prefix.child(target);
+        match self.store.head(&path).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(source) => Err(source).context(error::HeadTargetSnafu { target }),
+        }
+    }
+
+    async fn digest(&self, target: &str) -> Result<String, Error> {
+        let path = self.prefix.child(target);
+        let mut chunks = self
+            .store
+            .get(&path)
+            .await
+            .context(error::GetTargetSnafu { target })?
+            .into_stream();
+
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.context(error::GetTargetSnafu { target })?;
+            hasher.update(&chunk);
+        }
+        Ok(format!("sha256:{:x}", hasher.finalize()))
+    }
+}
+
+pub(crate) mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(crate)))]
+    pub(crate) enum Error {
+        #[snafu(display("Failed to read existing target '{}' from storage: {}", target, source))]
+        GetTarget {
+            target: String,
+            source: object_store::Error,
+        },
+
+        #[snafu(display("Failed to check for existing target '{}' in storage: {}", target, source))]
+        HeadTarget {
+            target: String,
+            source: object_store::Error,
+        },
+
+        #[snafu(display("'{}' is not a valid local path or storage URI", outdir))]
+        InvalidOutdir { outdir: String },
+
+        #[snafu(display("Failed to prepare local outdir '{}': {}", outdir, source))]
+        LocalOutdir {
+            outdir: String,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to open local file for target '{}': {}", target, source))]
+        OpenLocalFile {
+            target: String,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to parse --outdir '{}' as a storage location: {}", outdir, source))]
+        ParseOutdir {
+            outdir: String,
+            source: object_store::Error,
+        },
+
+        #[snafu(display("Failed to write target '{}' to storage: {}", target, source))]
+        PutTarget {
+            target: String,
+            source: object_store::Error,
+        },
+
+        #[snafu(display("Failed to read local target '{}': {}", target, source))]
+        ReadLocalFile {
+            target: String,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to write local target '{}': {}", target, source))]
+        WriteLocalFile {
+            target: String,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to stream target '{}' to storage: {}", target, source))]
+        WriteMultipart {
+            target: String,
+            source: std::io::Error,
+        },
+    }
+}
+pub(crate) use error::Error;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_file_sink_publishes_atomically() {
+        let outdir = tempfile::tempdir().unwrap();
+        let sink = LocalFileSink::new(outdir.path().to_path_buf()).unwrap();
+
+        let staged = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(staged.path(), b"image bytes").unwrap();
+
+        assert!(!sink.exists("my-target.img.lz4").await.unwrap());
+
+        sink.put_file("my-target.img.lz4", staged.path())
+            .await
+            .unwrap();
+
+        // The final target exists with the right content, and no stray `.part` file is left
+        // behind once the rename has completed.
+        assert!(sink.exists("my-target.img.lz4").await.unwrap());
+        assert_eq!(
+            std::fs::read(outdir.path().join("my-target.img.lz4")).unwrap(),
+            b"image bytes"
+        );
+        assert!(!outdir.path().join("my-target.img.lz4.part").exists());
+    }
+
+    #[tokio::test]
+    async fn local_file_sink_digest_matches_content_spanning_multiple_chunks() {
+        let outdir = tempfile::tempdir().unwrap();
+        let sink = LocalFileSink::new(outdir.path().to_path_buf()).unwrap();
+
+        // Bigger than HASH_CHUNK_SIZE, so digest() has to loop over more than one read.
+        let content = vec![0x42u8; HASH_CHUNK_SIZE * 2 + 17];
+        std::fs::write(outdir.path().join("my-target.img"), &content).unwrap();
+
+        let mut hasher = Sha256::new();
+        hasher.update(&content);
+        let expected = format!("sha256:{:x}", hasher.finalize());
+
+        assert_eq!(sink.digest("my-target.img").await.unwrap(), expected);
+    }
+}